@@ -6,7 +6,23 @@
 //!
 //! The procedural macro here is relatively simple, it simply appends a
 //! `#[test]` function to the original token stream which asserts that the
-//! function itself contains the relevant instruction.
+//! function itself contains the relevant instruction. Several rows may be
+//! given, separated by `;`, each optionally followed by a string literal
+//! naming it; one such test is generated per row, and all of them are
+//! placed inside a `mod <fn_name>_asserts`. A row may also carry
+//! `count = N` to require the instruction appear exactly `N` times, or
+//! `not = <instr>` to require that a given instruction is absent. A row's
+//! mnemonic may itself be a set, e.g. `vpand | pand` or `any(vpand, pand)`,
+//! in which case the assertion passes if any one of them is found. A row
+//! may opt into a runtime correctness check with `check = <expr>` (or
+//! `check_with = path::to::fn`), which additionally invokes the bound
+//! function and compares its result once the host's target features are
+//! confirmed to be present, skipping the runtime half otherwise. Instead of
+//! listing rows inline, `#[assert_instr(from = "path/to/fixture.txt")]`
+//! loads one row per non-empty line of a file (relative to
+//! `CARGO_MANIFEST_DIR`; lines starting with `#` are skipped),
+//! each line giving a mnemonic followed by argument values bound
+//! positionally onto the function's parameters.
 
 #![feature(proc_macro)]
 
@@ -18,6 +34,10 @@ extern crate syn;
 #[macro_use]
 extern crate synom;
 
+use std::env;
+use std::fs;
+use std::path::Path;
+
 use proc_macro2::TokenStream;
 
 #[proc_macro_attribute]
@@ -33,45 +53,37 @@ pub fn assert_instr(
         _ => panic!("must be attached to a function"),
     };
 
-    let instr = &invoc.instr;
+    let rows = match invoc {
+        Invoc::Rows(rows) => rows,
+        Invoc::FromFile(path) => rows_from_fixture(&path, func),
+    };
+    let rows = rows
+        .into_iter()
+        .map(|row| resolve_options(row, func))
+        .collect::<Vec<_>>();
+
     let maybe_ignore = if cfg!(optimized) {
         TokenStream::empty()
     } else {
         (quote! { #[ignore] }).into()
     };
     let name = &func.ident;
-    let assert_name = syn::Ident::from(
-        &format!("assert_{}_{}", name.sym.as_str(), instr.sym.as_str())[..],
-    );
-    let shim_name =
-        syn::Ident::from(&format!("{}_shim", name.sym.as_str())[..]);
-    let (to_test, test_name) = if invoc.args.len() == 0 {
-        (TokenStream::empty(), &func.ident)
-    } else {
-        let mut inputs = Vec::new();
-        let mut input_vals = Vec::new();
-        let ret = &func.decl.output;
-        for arg in func.decl.inputs.iter() {
-            let capture = match **arg.item() {
-                syn::FnArg::Captured(ref c) => c,
-                _ => panic!("arguments must not have patterns"),
-            };
-            let ident = match capture.pat {
-                syn::Pat::Ident(ref i) => &i.ident,
-                _ => panic!("must have bare arguments"),
-            };
-            match invoc.args.iter().find(|a| a.0 == ident.sym.as_str()) {
-                Some(&(_, ref tts)) => {
-                    input_vals.push(quote! { #tts });
-                }
-                None => {
-                    inputs.push(capture);
-                    input_vals.push(quote! { #ident });
-                }
-            };
-        }
+    let mod_name = syn::Ident::from(&format!("{}_asserts", name.sym.as_str())[..]);
 
-        let attrs = func.attrs
+    let asserts = rows.iter().enumerate().map(|(i, row)| {
+        let instrs = &row.instrs;
+        let assert_name = syn::Ident::from(&match row.name {
+            Some(ref name) => format!("case_{}", sanitize(&name.value())),
+            None => format!(
+                "case_{}_{}",
+                i,
+                instrs.iter().map(|i| i.sym.as_str()).collect::<Vec<_>>().join("_or_"),
+            ),
+        }[..]);
+        let shim_name = syn::Ident::from(
+            &format!("{}_shim_{}", name.sym.as_str(), i)[..],
+        );
+        let target_attrs = func.attrs
             .iter()
             .filter(|attr| {
                 attr.path
@@ -84,29 +96,118 @@ pub fn assert_instr(
                     .starts_with("target")
             })
             .collect::<Vec<_>>();
-        let attrs = Append(&attrs);
-        (
+
+        let (to_test, test_name, unbound) = if row.args.len() == 0 {
+            (TokenStream::empty(), name.clone(), func.decl.inputs.len())
+        } else {
+            let mut inputs = Vec::new();
+            let mut input_vals = Vec::new();
+            let ret = &func.decl.output;
+            for arg in func.decl.inputs.iter() {
+                let capture = match **arg.item() {
+                    syn::FnArg::Captured(ref c) => c,
+                    _ => panic!("arguments must not have patterns"),
+                };
+                let ident = match capture.pat {
+                    syn::Pat::Ident(ref i) => &i.ident,
+                    _ => panic!("must have bare arguments"),
+                };
+                match row.args.iter().find(|a| a.0 == ident.sym.as_str()) {
+                    Some(&(_, ref tts)) => {
+                        input_vals.push(quote! { #tts });
+                    }
+                    None => {
+                        inputs.push(capture);
+                        input_vals.push(quote! { #ident });
+                    }
+                };
+            }
+
+            let unbound = inputs.len();
+            let attrs = Append(&target_attrs);
+            (
+                quote! {
+                    #attrs
+                    unsafe fn #shim_name(#(#inputs),*) #ret {
+                        super::#name(#(#input_vals),*)
+                    }
+                }.into(),
+                shim_name,
+                unbound,
+            )
+        };
+
+        let check = match (&row.check, &row.check_with) {
+            (&Some(ref expr), &None) => Some(quote! { #expr }),
+            (&None, &Some(ref path)) => Some(quote! { #path() }),
+            (&None, &None) => None,
+            (&Some(_), &Some(_)) => {
+                panic!("cannot specify both `check` and `check_with`")
+            }
+        };
+        let check = check.map(|expected| {
+            if unbound != 0 {
+                panic!(
+                    "`check`/`check_with` requires every argument of `{}` \
+                     to be bound via `a = expr`",
+                    name.sym.as_str(),
+                );
+            }
+            let features = target_features(&target_attrs);
+            let detect = features
+                .iter()
+                .map(|f| {
+                    let macro_name = syn::Ident::from(
+                        &format!("is_{}_feature_detected", f)[..],
+                    );
+                    quote! { #macro_name!(#f) }
+                })
+                .collect::<Vec<_>>();
             quote! {
-                #attrs
-                unsafe fn #shim_name(#(#inputs),*) #ret {
-                    #name(#(#input_vals),*)
+                if true #(&& #detect)* {
+                    unsafe {
+                        assert_eq!(#test_name(), #expected);
+                    }
                 }
-            }.into(),
-            &shim_name,
-        )
-    };
+            }
+        }).unwrap_or_else(TokenStream::empty);
+
+        let count = match row.count {
+            Some(ref count) => quote! { Some(#count) },
+            None => quote! { None },
+        };
+        let not = match row.not {
+            Some(ref not) => quote! { Some(stringify!(#not)) },
+            None => quote! { None },
+        };
+        let instr_strs = instrs.iter().map(|instr| quote! { stringify!(#instr) });
+
+        quote_spanned! {
+            proc_macro2::Span::call_site(),
+            #[test]
+            #[allow(non_snake_case)]
+            #maybe_ignore
+            fn #assert_name() {
+                #to_test
 
-    let tts: TokenStream = quote_spanned! {
-        proc_macro2::Span::call_site(),
-        #[test]
-        #[allow(non_snake_case)]
-        #maybe_ignore
-        fn #assert_name() {
-            #to_test
-
-            ::stdsimd_test::assert(#test_name as usize,
-                                   stringify!(#test_name),
-                                   stringify!(#instr));
+                ::stdsimd_test::assert(#test_name as usize,
+                                       stringify!(#test_name),
+                                       &[#(#instr_strs),*],
+                                       #count,
+                                       #not);
+
+                #check
+            }
+        }
+    });
+    let asserts = Append(asserts.collect::<Vec<_>>());
+
+    let tts: TokenStream = quote! {
+        #[cfg(test)]
+        mod #mod_name {
+            use super::*;
+
+            #asserts
         }
     }.into();
     // why? necessary now to get tests to work?
@@ -119,28 +220,296 @@ pub fn assert_instr(
     tts.into()
 }
 
-struct Invoc {
-    instr: syn::Ident,
+/// Turns a human-readable test name (the optional trailing string literal
+/// in an `#[assert_instr]` row) into something usable as an identifier.
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Pulls the feature names out of `#[target_feature(enable = "...")]`, the
+/// same attribute the `simd_test` macro probes via `is_*_feature_detected!`
+/// before running feature-gated code.
+fn target_features(attrs: &[&syn::Attribute]) -> Vec<String> {
+    let mut features = Vec::new();
+    for attr in attrs {
+        let meta = match attr.interpret_meta() {
+            Some(meta) => meta,
+            None => continue,
+        };
+        let list = match meta {
+            syn::Meta::List(ref list) if list.ident == "target_feature" => {
+                list
+            }
+            _ => continue,
+        };
+        for item in &list.nested {
+            let nv = match *item {
+                syn::NestedMeta::Meta(syn::Meta::NameValue(ref nv))
+                    if nv.ident == "enable" =>
+                {
+                    nv
+                }
+                _ => continue,
+            };
+            if let syn::Lit::Str(ref s) = nv.lit {
+                features.extend(
+                    s.value().split(',').map(|f| f.trim().to_string()),
+                );
+            }
+        }
+    }
+    features
+}
+
+/// Sorts a parsed row's `name = expr` bindings into real arguments of
+/// `func` versus the `count`/`not`/`check`/`check_with` options, which
+/// share the same `name = expr` syntax. A real parameter always wins this
+/// resolution, so an intrinsic with e.g. a `count: __m128i` operand can
+/// still bind it with `count = ...` without it being misread as the
+/// occurrence-count option.
+fn resolve_options(row: InvocRow, func: &syn::ItemFn) -> InvocRow {
+    let param_names = func.decl
+        .inputs
+        .iter()
+        .map(|arg| {
+            let capture = match **arg.item() {
+                syn::FnArg::Captured(ref c) => c,
+                _ => panic!("arguments must not have patterns"),
+            };
+            match capture.pat {
+                syn::Pat::Ident(ref i) => i.ident.sym.as_str().to_string(),
+                _ => panic!("must have bare arguments"),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut args = Vec::new();
+    let mut count = row.count;
+    let mut not = row.not;
+    let mut check = row.check;
+    let mut check_with = row.check_with;
+    for (name, expr) in row.args {
+        if param_names.iter().any(|p| p == name.sym.as_str()) {
+            args.push((name, expr));
+            continue;
+        }
+        match name.sym.as_str() {
+            "count" => count = Some(expr),
+            "not" => not = Some(expr),
+            "check" => check = Some(expr),
+            "check_with" => {
+                check_with = Some(match expr {
+                    syn::Expr::Path(p) => p.path,
+                    _ => panic!("`check_with` expects a path"),
+                });
+            }
+            _ => args.push((name, expr)),
+        }
+    }
+
+    InvocRow {
+        instrs: row.instrs,
+        args,
+        count,
+        not,
+        check,
+        check_with,
+        name: row.name,
+    }
+}
+
+/// Reads `#[assert_instr(from = "...")]`'s fixture file and turns each
+/// non-empty line not starting with `#` into a row: the first
+/// whitespace-separated token is the
+/// mnemonic, the rest are argument values bound positionally onto `func`'s
+/// parameters.
+fn rows_from_fixture(path: &syn::LitStr, func: &syn::ItemFn) -> Vec<InvocRow> {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR")
+        .expect("CARGO_MANIFEST_DIR not set");
+    let full_path = Path::new(&manifest_dir).join(path.value());
+    let contents = fs::read_to_string(&full_path).unwrap_or_else(|e| {
+        panic!("failed to read assert_instr fixture {}: {}", full_path.display(), e)
+    });
+
+    contents
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let lineno = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+            let mut words = line.split_whitespace();
+            let instr = words.next().unwrap_or_else(|| {
+                panic!("{}:{}: expected a mnemonic", full_path.display(), lineno)
+            });
+            let instr = syn::parse_str::<syn::Ident>(instr).unwrap_or_else(|_| {
+                panic!(
+                    "{}:{}: `{}` is not a valid mnemonic",
+                    full_path.display(),
+                    lineno,
+                    instr,
+                )
+            });
+
+            let words = words.collect::<Vec<_>>();
+            if words.len() != func.decl.inputs.len() {
+                panic!(
+                    "{}:{}: expected {} argument(s), found {}",
+                    full_path.display(),
+                    lineno,
+                    func.decl.inputs.len(),
+                    words.len(),
+                );
+            }
+
+            let args = func.decl
+                .inputs
+                .iter()
+                .zip(words)
+                .map(|(arg, word)| {
+                    let capture = match **arg.item() {
+                        syn::FnArg::Captured(ref c) => c,
+                        _ => panic!("arguments must not have patterns"),
+                    };
+                    let ident = match capture.pat {
+                        syn::Pat::Ident(ref i) => i.ident.clone(),
+                        _ => panic!("must have bare arguments"),
+                    };
+                    let expr =
+                        syn::parse_str::<syn::Expr>(word).unwrap_or_else(|_| {
+                            panic!(
+                                "{}:{}: `{}` is not a valid argument",
+                                full_path.display(),
+                                lineno,
+                                word,
+                            )
+                        });
+                    (ident, expr)
+                })
+                .collect();
+
+            Some(InvocRow {
+                instrs: vec![instr],
+                args,
+                count: None,
+                not: None,
+                check: None,
+                check_with: None,
+                name: None,
+            })
+        })
+        .collect()
+}
+
+enum Invoc {
+    Rows(Vec<InvocRow>),
+    FromFile(syn::LitStr),
+}
+
+struct InvocRow {
+    /// The set of mnemonics this row accepts; the assertion passes if any
+    /// one of them is found, e.g. `vpand | pand` or `any(vpand, pand)`.
+    instrs: Vec<syn::Ident>,
     args: Vec<(syn::Ident, syn::Expr)>,
+    /// `count = N`: the instruction must appear exactly `N` times.
+    count: Option<syn::Expr>,
+    /// `not = <instr>`: the instruction must not appear at all.
+    not: Option<syn::Expr>,
+    /// `check = <expr>`: the runtime result must equal this expression.
+    check: Option<syn::Expr>,
+    /// `check_with = <path>`: the runtime result must equal the return
+    /// value of calling this path with no arguments.
+    check_with: Option<syn::Path>,
+    name: Option<syn::LitStr>,
+}
+
+enum Segment {
+    Case(Vec<syn::Ident>, Vec<(syn::Ident, syn::Expr)>),
+    Name(syn::LitStr),
 }
 
 impl synom::Synom for Invoc {
-    named!(parse -> Self, map!(parens!(do_parse!(
-        instr: syn!(syn::Ident) >>
-        args: many0!(do_parse!(
-            syn!(syn::tokens::Comma) >>
-            name: syn!(syn::Ident) >>
+    named!(parse -> Self, map!(parens!(alt!(
+        do_parse!(
+            keyword!(from) >>
             syn!(syn::tokens::Eq) >>
-            expr: syn!(syn::Expr) >>
-            (name, expr)
-        )) >>
-        (Invoc {
-            instr,
-            args,
-        })
+            path: syn!(syn::LitStr) >>
+            (Invoc::FromFile(path))
+        )
+        |
+        call!(parse_rows)
     )), |p| p.0));
 }
 
+named!(parse_rows -> Invoc, map!(
+    call!(synom::separated_list!(syn!(syn::tokens::Semi), syn!(Segment))),
+    |segments| {
+        // `count`/`not`/`check`/`check_with` are only resolved to options
+        // (rather than real parameter bindings) once the annotated
+        // function is known, in `resolve_options` above: at this point we
+        // don't have `func` yet, so every `name = expr` pair is kept as a
+        // plain binding.
+        let mut rows = Vec::new();
+        for segment in segments {
+            match segment {
+                Segment::Case(instrs, args) => {
+                    rows.push(InvocRow {
+                        instrs,
+                        args,
+                        count: None,
+                        not: None,
+                        check: None,
+                        check_with: None,
+                        name: None,
+                    });
+                }
+                Segment::Name(name) => {
+                    let row = rows.last_mut()
+                        .expect("a test name must follow a case");
+                    row.name = Some(name);
+                }
+            }
+        }
+        Invoc::Rows(rows)
+    }
+));
+
+impl synom::Synom for Segment {
+    named!(parse -> Self, alt!(
+        map!(syn!(syn::LitStr), Segment::Name)
+        |
+        map!(do_parse!(
+            instrs: call!(parse_instrs) >>
+            args: many0!(do_parse!(
+                syn!(syn::tokens::Comma) >>
+                name: syn!(syn::Ident) >>
+                syn!(syn::tokens::Eq) >>
+                expr: syn!(syn::Expr) >>
+                (name, expr)
+            )) >>
+            (instrs, args)
+        ), |(instrs, args)| Segment::Case(instrs, args))
+    ));
+}
+
+/// Parses either a single mnemonic, a `|`-separated alternation of
+/// mnemonics, or an explicit `any(a, b, ...)` list.
+named!(parse_instrs -> Vec<syn::Ident>, alt!(
+    do_parse!(
+        keyword!(any) >>
+        list: parens!(call!(
+            synom::separated_nonempty_list!(syn!(syn::tokens::Comma), syn!(syn::Ident))
+        )) >>
+        (list.0)
+    )
+    |
+    call!(synom::separated_nonempty_list!(syn!(syn::tokens::Or), syn!(syn::Ident)))
+));
+
 struct Append<T>(T);
 
 impl<T> quote::ToTokens for Append<T>